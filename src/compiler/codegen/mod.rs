@@ -2,15 +2,64 @@
 /// the AST provided by the parser. The instructions are being stored in
 /// an appropriate data structure, this module is not concerned with
 /// packing code into modules.
-use std::collections::HashMap;
+mod optimize;
+
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use common::*;
 use compiler::parser::{Expression, Expression::*};
+pub use self::optimize::OptimizationLevel;
+use self::optimize::{fold_constants, is_pure_body, eval_call, EVAL_STEP_BUDGET, FuncTable, FunctionInfo};
 
 /// Structure for performing optimizations
 struct OptimizationInfo<'a> {
     func_name: &'a str,
     tail: bool,
+    level: OptimizationLevel,
+}
+
+/// Hands out registers from the 256-register file for a single call frame
+/// (one top-level expression or function body), reusing freed registers
+/// instead of growing monotonically with expression nesting.
+///
+/// # Remarks
+///
+/// Registers below the allocator's starting point are reserved by the
+/// caller (e.g. the frame's destination register, or parameters pinned for
+/// the lifetime of a function/`let` body) and are never handed out here.
+struct RegisterAllocator {
+    free: Vec<u8>,
+    next: u16,
+}
+
+impl RegisterAllocator {
+    /// Create an allocator that will only ever hand out registers numbered
+    /// `reserved` and above.
+    fn new(reserved: u8) -> RegisterAllocator {
+        RegisterAllocator {
+            free: Vec::new(),
+            next: reserved as u16
+        }
+    }
+
+    /// Allocate a scratch register, reusing a freed one when possible.
+    fn alloc(&mut self) -> u8 {
+        if let Some(reg) = self.free.pop() {
+            return reg;
+        }
+        if self.next > 0xFF {
+            panic!("Register pressure exceeded.");
+        }
+        let reg = self.next as u8;
+        self.next += 1;
+        reg
+    }
+
+    /// Return a scratch register to the pool once the parent expression has
+    /// consumed its value.
+    fn free(&mut self, reg: u8) {
+        self.free.push(reg);
+    }
 }
 
 /// Generate a module from the abstract syntax tree.
@@ -21,11 +70,40 @@ struct OptimizationInfo<'a> {
 ///
 /// # Remarks
 ///
+/// Shorthand for `generate_with_level` at `OptimizationLevel::None`: no
+/// constant folding or branch rewriting is applied and expressions are
+/// lowered one-to-one. Unreachable functions are still dropped.
+pub fn generate(expressions: &[Expression]) -> Module {
+    generate_with_level(expressions, OptimizationLevel::None)
+}
+
+/// Generate a module from the abstract syntax tree, running AST-level
+/// optimization passes first according to `level`.
+///
+/// # Arguments
+///
+/// * `expressions` - All top level expressions (AST roots) generated by the parser
+/// * `level` - Optimization level controlling which passes run before codegen
+///
+/// # Remarks
+///
 /// Function definitions are processed first and placed at the beginning of the
 /// module, which allows for easier processing. The entry point of the module
 /// points to the first top level expression being evaluated.
-pub fn generate(expressions: &[Expression]) -> Module {
-    let mut func: HashMap<String, u32> = HashMap::new();
+///
+/// Only functions reachable from the top-level expressions (transitively,
+/// through the functions they call) are emitted; the rest are dead code
+/// and are dropped so function-table indices stay dense.
+pub fn generate_with_level(expressions: &[Expression], level: OptimizationLevel) -> Module {
+    let folded;
+    let expressions = if level >= OptimizationLevel::Simple {
+        folded = expressions.iter().map(fold_constants).collect::<Vec<_>>();
+        folded.as_slice()
+    } else {
+        expressions
+    };
+
+    let mut func: FuncTable = HashMap::new();
     let vars: HashMap<String, (Type, Register)> = HashMap::new();
     let mut module = Module {
         functions: Vec::new(),
@@ -37,16 +115,20 @@ pub fn generate(expressions: &[Expression]) -> Module {
     // Initial optimization info structure
     let oinfo = OptimizationInfo {
         func_name: "NONE",
-        tail: false
+        tail: false,
+        level
     };
 
-    // Process function definitions first
+    // Process function definitions first, but only those reachable from the
+    // top-level expressions; unreachable definitions are dead code
+    let reachable = reachable_functions(expressions);
     let filtered = expressions.iter().filter(|&x| match *x {
-        FunctionDefinition(_,_,_) => true,
+        FunctionDefinition(ref name, _, _) => reachable.contains(name.as_str()),
         _ => false
     });
     for expr in filtered {
-        generate_expression(expr, reg::VAL, &mut func, &vars, &mut module, &oinfo);
+        let mut alloc = RegisterAllocator::new(reg::VAL + 1);
+        generate_expression(expr, reg::VAL, &mut func, &vars, &mut module, &oinfo, &mut alloc);
     }
 
     // Process top-level expressions to be evaluated
@@ -56,7 +138,8 @@ pub fn generate(expressions: &[Expression]) -> Module {
         _ => true
     });
     for expr in filtered {
-        generate_expression(expr, reg::VAL, &mut func, &vars, &mut module, &oinfo);
+        let mut alloc = RegisterAllocator::new(reg::VAL + 1);
+        generate_expression(expr, reg::VAL, &mut func, &vars, &mut module, &oinfo, &mut alloc);
     }
 
     // Always end with halt instruction
@@ -70,6 +153,100 @@ pub fn generate(expressions: &[Expression]) -> Module {
     module
 }
 
+/// Visit `expr` and every descendant in depth-first order, calling `visit`
+/// on each node.
+///
+/// # Arguments
+///
+/// * `expr` - Root of the AST to walk
+/// * `visit` - Called on each node; returning `false` skips descending into that node's children
+pub fn walk(expr: &Expression, visit: &mut FnMut(&Expression) -> bool) {
+    if !visit(expr) {
+        return;
+    }
+
+    match *expr {
+        Integer(_) | Variable(_) | NullaryOp(_) => {}
+        BinaryOp(_, ref left, ref right) => {
+            walk(left, visit);
+            walk(right, visit);
+        }
+        UnaryOp(_, ref left) => {
+            walk(left, visit);
+        }
+        Function(_, ref param) => {
+            for p in param {
+                walk(p, visit);
+            }
+        }
+        FunctionDefinition(_, _, ref body) => {
+            for expr in body {
+                walk(expr, visit);
+            }
+        }
+        VariableAssignment(ref assignments, ref body) => {
+            for &(_, ref expr) in assignments {
+                walk(expr, visit);
+            }
+            for expr in body {
+                walk(expr, visit);
+            }
+        }
+        Conditional(ref cond, ref yes, ref no) => {
+            walk(cond, visit);
+            for expr in yes {
+                walk(expr, visit);
+            }
+            for expr in no {
+                walk(expr, visit);
+            }
+        }
+    }
+}
+
+/// Collect the set of function names reachable from the top-level
+/// (non-definition) expressions, transitively following calls into the
+/// bodies of the functions they call.
+fn reachable_functions(expressions: &[Expression]) -> HashSet<String> {
+    let bodies: HashMap<&str, &[Expression]> = expressions.iter()
+        .filter_map(|x| match *x {
+            FunctionDefinition(ref name, _, ref body) => Some((name.as_str(), body.as_slice())),
+            _ => None
+        })
+        .collect();
+
+    let mut reachable = HashSet::new();
+    let mut frontier = Vec::new();
+    for expr in expressions {
+        if let FunctionDefinition(_, _, _) = *expr {
+            continue;
+        }
+        collect_calls(expr, &mut frontier);
+    }
+
+    while let Some(name) = frontier.pop() {
+        if reachable.insert(name.clone()) {
+            if let Some(body) = bodies.get(name.as_str()) {
+                for expr in body.iter() {
+                    collect_calls(expr, &mut frontier);
+                }
+            }
+        }
+    }
+
+    reachable
+}
+
+/// Collect the names of every function called anywhere in `expr` into `out`.
+fn collect_calls(expr: &Expression, out: &mut Vec<String>) {
+    walk(expr, &mut |e| {
+        if let Function(ref name, _) = *e {
+            out.push(name.clone());
+        }
+        true
+    });
+}
+
 /// Generate instructions for an AST with expression as its root node.
 ///
 /// # Arguments
@@ -80,12 +257,14 @@ pub fn generate(expressions: &[Expression]) -> Module {
 /// * `vars` - A variable assignment for all child expressions
 /// * `module` - Module to be filled with constant/function/code storage
 /// * `oinfo` - Information used for optimization
+/// * `alloc` - Scratch register allocator for the enclosing call frame
 fn generate_expression(expr: &Expression,
                        base: u8,
-                       func: &mut HashMap<String, u32>,
+                       func: &mut FuncTable,
                        vars: &HashMap<String, (Type, Register)>,
                        module: &mut Module,
-                       oinfo: &OptimizationInfo) {
+                       oinfo: &OptimizationInfo,
+                       alloc: &mut RegisterAllocator) {
     match *expr {
         Integer(i) => {
             expr_integer(i, base, module);
@@ -93,42 +272,46 @@ fn generate_expression(expr: &Expression,
         BinaryOp(ref op, ref left, ref right) => {
             let optimizations = OptimizationInfo {
                 func_name: oinfo.func_name,
-                tail: false
+                tail: false,
+                level: oinfo.level
             };
-            expr_binary(op, left, right, base, func, vars, module, &optimizations);
+            expr_binary(op, left, right, base, func, vars, module, &optimizations, alloc);
         }
         UnaryOp(ref op, ref left) => {
             let optimizations = OptimizationInfo {
                 func_name: oinfo.func_name,
-                tail: false
+                tail: false,
+                level: oinfo.level
             };
-            expr_unary(op, left, base, func, vars, module, &optimizations);
+            expr_unary(op, left, base, func, vars, module, &optimizations, alloc);
         }
         NullaryOp(ref op) => {
             expr_nullary(op, base, module);
         }
         Function(ref name, ref param) => {
-            expr_call(name, param, base, func, vars, module, oinfo);
+            expr_call(name, param, base, func, vars, module, oinfo, alloc);
         }
         FunctionDefinition(ref name, ref param, ref body) => {
             let optimizations = OptimizationInfo {
                 func_name: name,
-                tail: true
+                tail: true,
+                level: oinfo.level
             };
             expr_fundef(name, param, body, base, func, vars, module, &optimizations);
         }
         VariableAssignment(ref assignments, ref body) => {
             let optimizations = OptimizationInfo {
                 func_name: oinfo.func_name,
-                tail: false
+                tail: false,
+                level: oinfo.level
             };
-            expr_varass(assignments, body, base, func, vars, module, &optimizations);
+            expr_varass(assignments, body, base, func, vars, module, &optimizations, alloc);
         }
         Variable(ref name) => {
             expr_variable(name, base, vars, module);
         }
         Conditional(ref condition, ref yes, ref no) => {
-            expr_conditional(condition, yes, no, base, func, vars, module, &oinfo);
+            expr_conditional(condition, yes, no, base, func, vars, module, &oinfo, alloc);
         }
     }
 }
@@ -191,25 +374,27 @@ fn expr_integer(value: i64,
 /// * `vars` - A variable assignment for all child expressions
 /// * `module` - Module to be filled with constant/function/code storage
 /// * `oinfo` - Information needed for optimizations
+/// * `alloc` - Scratch register allocator for the enclosing call frame
 #[inline(always)]
 fn expr_binary(op: &str,
                left: &Expression,
                right: &Expression,
                base: u8,
-               func: &mut HashMap<String, u32>,
+               func: &mut FuncTable,
                vars: &HashMap<String, (Type, Register)>,
                module: &mut Module,
-               oinfo: &OptimizationInfo) {
-    let reg_left = base + 1;
-    generate_expression(left, reg_left, func, vars, module, oinfo);
-    let reg_right = base + 2;
-    generate_expression(right, reg_right, func, vars, module, oinfo);
+               oinfo: &OptimizationInfo,
+               alloc: &mut RegisterAllocator) {
+    let reg_left = alloc.alloc();
+    generate_expression(left, reg_left, func, vars, module, oinfo, alloc);
+    let reg_right = alloc.alloc();
+    generate_expression(right, reg_right, func, vars, module, oinfo, alloc);
 
     let mut instruction = Instruction {
         opcode: ops::HLT,
         target: base,
-        left: base + 1,
-        right: base + 2
+        left: reg_left,
+        right: reg_right
     };
 
     match op.as_ref() {
@@ -229,6 +414,8 @@ fn expr_binary(op: &str,
     }
 
     module.code.push(instruction);
+    alloc.free(reg_right);
+    alloc.free(reg_left);
 }
 
 /// Generate instructions for an unary operation.
@@ -242,21 +429,23 @@ fn expr_binary(op: &str,
 /// * `vars` - A variable assignment for all child expressions
 /// * `module` - Module to be filled with constant/function/code storage
 /// * `oinfo` - Information needed for optimizations
+/// * `alloc` - Scratch register allocator for the enclosing call frame
 #[inline(always)]
 fn expr_unary(op: &str,
               left: &Expression,
               base: u8,
-              func: &mut HashMap<String, u32>,
+              func: &mut FuncTable,
               vars: &HashMap<String, (Type, Register)>,
               module: &mut Module,
-              oinfo: &OptimizationInfo) {
-    let reg_left = base + 1;
-    generate_expression(left, reg_left, func, vars, module, oinfo);
+              oinfo: &OptimizationInfo,
+              alloc: &mut RegisterAllocator) {
+    let reg_left = alloc.alloc();
+    generate_expression(left, reg_left, func, vars, module, oinfo, alloc);
 
     let mut instruction = Instruction {
         opcode: ops::HLT,
         target: base,
-        left: base + 1,
+        left: reg_left,
         right: 0
     };
 
@@ -267,6 +456,7 @@ fn expr_unary(op: &str,
     }
 
     module.code.push(instruction);
+    alloc.free(reg_left);
 }
 
 /// Generate instructions for a nullary operation.
@@ -307,58 +497,83 @@ fn expr_nullary(op: &str,
 /// * `vars` - A variable assignment for all child expressions
 /// * `module` - Module to be filled with constant/function/code storage
 /// * `oinfo` - Information needed for optimization
+///
+/// # Remarks
+///
+/// At `OptimizationLevel::Full`, a call to a pure function with all-constant
+/// arguments is evaluated at compile time and lowered to a single load of
+/// the result instead of a `CAL`/`LDR` sequence.
 #[inline(always)]
 fn expr_call(name: &str,
              param: &[Expression],
              base: u8,
-             func: &mut HashMap<String, u32>,
+             func: &mut FuncTable,
              vars: &HashMap<String, (Type, Register)>,
              module: &mut Module,
-             oinfo: &OptimizationInfo) {
+             oinfo: &OptimizationInfo,
+             alloc: &mut RegisterAllocator) {
+    if oinfo.level == OptimizationLevel::Full {
+        if let Some(value) = try_eval_call(name, param, func) {
+            expr_integer(value, base, module);
+            return;
+        }
+    }
+
     let index = {
         match func.get(name) {
-            Some(index) => *index,
+            Some(entry) => entry.index,
             _ => panic!("Function {} is not defined", name)
         }
     };
 
     // Process each parameter expression before making the actual call
-    let mut tmp_base = base;
     let mut tmp_param = reg::VAL;
     let mut tmp_instructions: Vec<Instruction> = Vec::new();
+    let mut tmp_regs: Vec<u8> = Vec::new();
     let mut mov_instruction = if oinfo.tail {
         Instruction {
             opcode: ops::MOV,
             target: tmp_param,
-            left: tmp_base,
+            left: base,
             right: 0
         }
     } else {
         Instruction {
             opcode: ops::MVO,
             target: tmp_param,
-            left: tmp_base,
+            left: base,
             right: 0xFF
         }
     };
     let param_oinfo = OptimizationInfo {
         func_name: oinfo.func_name,
-        tail: false
+        tail: false,
+        level: oinfo.level
     };
 
     for p in param {
-        tmp_base += 1;
+        let tmp_base = alloc.alloc();
         tmp_param += 1;
-        generate_expression(p, tmp_base, func, vars, module, &param_oinfo);
+        generate_expression(p, tmp_base, func, vars, module, &param_oinfo, alloc);
 
         // Pass results to callee parameter registers
         mov_instruction.target = tmp_param;
         mov_instruction.left = tmp_base;
         tmp_instructions.push(mov_instruction.clone());
+        tmp_regs.push(tmp_base);
     }
 
     // Load results of parameter evaluation and make the call
+    //
+    // Registers are only freed now, after their MOV/MVO has actually been
+    // appended: freeing them eagerly inside the loop above would let the
+    // allocator hand an argument's register straight back out to the next
+    // argument's evaluation before the deferred instruction copying its
+    // value out had run, clobbering it.
     module.code.extend(tmp_instructions);
+    for reg in tmp_regs {
+        alloc.free(reg);
+    }
     if oinfo.tail {
         module.code.push(Instruction {
             opcode: ops::JMP,
@@ -382,6 +597,36 @@ fn expr_call(name: &str,
     }
 }
 
+/// Try to evaluate a call to `name` at compile time.
+///
+/// # Arguments
+///
+/// * `name` - Name of the called function
+/// * `param` - Argument expressions at the call site
+/// * `func` - Lookup table for function table entries
+///
+/// # Remarks
+///
+/// Only succeeds if `name` refers to a pure function and every argument has
+/// already folded down to an `Integer` literal. Returns `None` (and the
+/// caller falls back to a normal call) when the function is impure, takes a
+/// non-constant argument, or the eager evaluator runs out of step budget or
+/// hits a division by zero.
+#[inline(always)]
+fn try_eval_call(name: &str, param: &[Expression], func: &FuncTable) -> Option<i64> {
+    let entry = func.get(name)?;
+    if !entry.pure {
+        return None;
+    }
+
+    let args = param.iter()
+        .map(|p| match *p { Integer(value) => Some(value), _ => None })
+        .collect::<Option<Vec<i64>>>()?;
+
+    let mut budget = EVAL_STEP_BUDGET;
+    eval_call(entry, &args, func, &mut budget)
+}
+
 /// Generate instructions for a function definition.
 ///
 /// # Arguments
@@ -394,18 +639,30 @@ fn expr_call(name: &str,
 /// * `vars` - A variable assignment for all child expressions
 /// * `module` - Module to be filled with constant/function/code storage
 /// * `oinfo` - Information needed for optimization
+///
+/// # Remarks
+///
+/// The function is analyzed for purity (no `read`/`write`, no calls to
+/// impure functions; direct self-recursion is allowed) so that
+/// `OptimizationLevel::Full` can later evaluate calls to it at compile time.
 #[inline(always)]
 fn expr_fundef(name: &str,
                param: &[String],
                body: &[Expression],
                base: u8,
-               func: &mut HashMap<String, u32>,
+               func: &mut FuncTable,
                vars: &HashMap<String, (Type, Register)>,
                module: &mut Module,
                oinfo: &OptimizationInfo) {
     let index = func.len() as u32;
     let address = module.code.len() as u64;
-    func.insert(name.to_string(), index);
+    let pure = is_pure_body(name, body, func);
+    func.insert(name.to_string(), FunctionInfo {
+        index,
+        pure,
+        param: param.to_vec(),
+        body: body.to_vec()
+    });
     module.functions.push(address);
 
     let mut base = base;
@@ -417,8 +674,9 @@ fn expr_fundef(name: &str,
 
     let base = base;
     let vars = &vars;
+    let mut alloc = RegisterAllocator::new(base + 1);
     for expr in body {
-        generate_expression(expr, base, func, vars, module, oinfo);
+        generate_expression(expr, base, func, vars, module, oinfo, &mut alloc);
     }
 
     module.code.push(Instruction {
@@ -447,6 +705,7 @@ fn expr_fundef(name: &str,
 /// * `vars` - A variable assignment for all child expressions
 /// * `module` - Module to be filled with constant/function/code storage
 /// * `oinfo` - Information needed for optimization
+/// * `alloc` - Scratch register allocator for the enclosing call frame
 ///
 /// # Remarks
 ///
@@ -456,22 +715,25 @@ fn expr_fundef(name: &str,
 fn expr_varass(assignment: &[(String, Expression)],
                body: &[Expression],
                base: u8,
-               func: &mut HashMap<String, u32>,
+               func: &mut FuncTable,
                vars: &HashMap<String, (Type, Register)>,
                module: &mut Module,
-               oinfo: &OptimizationInfo) {
+               oinfo: &OptimizationInfo,
+               alloc: &mut RegisterAllocator) {
     let mut tmp_base = base;
+    let mut bound = Vec::new();
     let mut vars = vars.clone();
     for &(ref var, ref expr) in assignment {
-        tmp_base += 1;
-        generate_expression(expr, tmp_base, func, &vars, module, oinfo);
+        tmp_base = alloc.alloc();
+        generate_expression(expr, tmp_base, func, &vars, module, oinfo, alloc);
         vars.insert(var.to_string(), (types::INT, tmp_base));
+        bound.push(tmp_base);
     }
 
     let tmp_base = tmp_base;
     let vars = &vars;
     for expr in body {
-        generate_expression(expr, tmp_base, func, vars, module, oinfo);
+        generate_expression(expr, tmp_base, func, vars, module, oinfo, alloc);
     }
 
     module.code.push(Instruction {
@@ -480,6 +742,10 @@ fn expr_varass(assignment: &[(String, Expression)],
         left: tmp_base,
         right: 0
     });
+
+    for reg in bound {
+        alloc.free(reg);
+    }
 }
 
 /// Generate instructions for a variable use.
@@ -508,6 +774,267 @@ fn expr_variable(name: &str,
     });
 }
 
+/// Minimum number of `==` arms a chain needs before it is considered for
+/// jump-table dispatch; shorter chains stay a JTF/JMF cascade since a table
+/// isn't worth its own bookkeeping for one or two arms.
+const SWITCH_MIN_ARMS: usize = 3;
+
+/// Maximum spread of a chain's constants, as a multiple of its arm count,
+/// before the chain is considered too sparse for a table. A 3-arm chain
+/// whose constants span 50 values would otherwise force a 50-entry table
+/// that is almost entirely default-routing padding.
+const SWITCH_SPAN_FACTOR: i64 = 4;
+
+/// If `cond` is `<variable> == <constant>` (in either operand order),
+/// return the variable name and constant.
+fn switch_arm_test(cond: &Expression) -> Option<(&str, i64)> {
+    if let BinaryOp(ref op, ref left, ref right) = *cond {
+        if op == "==" {
+            return match (left.as_ref(), right.as_ref()) {
+                (&Variable(ref name), &Integer(value)) => Some((name.as_str(), value)),
+                (&Integer(value), &Variable(ref name)) => Some((name.as_str(), value)),
+                _ => None
+            };
+        }
+    }
+    None
+}
+
+/// Recognize `cond`/`yes`/`no` as the head of a chain of `==` conditionals
+/// against the same variable, and collect its arms.
+///
+/// # Remarks
+///
+/// Walks down the `no` branch as long as it is a single nested
+/// `Conditional` continuing the chain (same variable, a constant not seen
+/// before); the first branch that doesn't fit becomes the dispatch's
+/// default arm. Returns `None` when `cond` itself isn't an `==` test, or
+/// when fewer than `SWITCH_MIN_ARMS` arms were collected before the chain
+/// broke.
+fn collect_switch_arms<'a>(cond: &'a Expression,
+                          yes: &'a [Expression],
+                          no: &'a [Expression])
+                          -> Option<(&'a str, Vec<(i64, &'a [Expression])>, &'a [Expression])> {
+    let (var, value) = switch_arm_test(cond)?;
+
+    let mut seen = HashSet::new();
+    seen.insert(value);
+    let mut arms = vec![(value, yes)];
+    let mut default = no;
+
+    while default.len() == 1 {
+        let next = match default[0] {
+            Conditional(ref next_cond, ref next_yes, ref next_no) => {
+                match switch_arm_test(next_cond) {
+                    Some((next_var, next_value)) if next_var == var && seen.insert(next_value) => {
+                        Some((next_value, next_yes.as_slice(), next_no.as_slice()))
+                    }
+                    _ => None
+                }
+            }
+            _ => None
+        };
+
+        match next {
+            Some((value, next_yes, next_no)) => {
+                arms.push((value, next_yes));
+                default = next_no;
+            }
+            None => break
+        }
+    }
+
+    if arms.len() < SWITCH_MIN_ARMS {
+        return None;
+    }
+
+    Some((var, arms, default))
+}
+
+/// Compute the `(min, span)` of a chain's constants and decide whether it
+/// is dense enough to lower to a jump table.
+///
+/// # Remarks
+///
+/// `span` is `max - min + 1`, the size of the table the dispatch would
+/// need. Table entries are relative offsets that must fit in a signed
+/// 16-bit word (see `expr_switch`), and every arm's offset is at least
+/// `span` (the table sits between the `JTI` instruction and the arms it
+/// points into), so `span` itself is capped well below `i16::max_value()` to
+/// leave headroom for the arm bodies that follow. Also returns `None`
+/// when the span is more than `SWITCH_SPAN_FACTOR` times the number of
+/// arms (too sparse to be worth a table).
+fn switch_table_span(arms: &[(i64, &[Expression])]) -> Option<(i64, u16)> {
+    let min = arms.iter().map(|&(v, _)| v).min()?;
+    let max = arms.iter().map(|&(v, _)| v).max()?;
+    let span = max.checked_sub(min)?.checked_add(1)?;
+
+    if span <= 0 || span > i64::from(i16::max_value()) / 2 {
+        return None;
+    }
+    if span > arms.len() as i64 * SWITCH_SPAN_FACTOR {
+        return None;
+    }
+
+    Some((min, span as u16))
+}
+
+/// Generate instructions for a recognized chain of `==` conditionals
+/// against one variable, dispatching through a jump table instead of a
+/// linear JTF/JMF cascade.
+///
+/// # Arguments
+///
+/// * `var` - Name of the variable every arm compares against
+/// * `arms` - `(constant, arm body)` pairs in chain order
+/// * `min` - Smallest constant across `arms`
+/// * `span` - `max - min + 1`, the number of entries in the table
+/// * `default` - Body run when the scrutinee matches none of `arms`
+/// * `base` - Base register of the expression, return value is stored here
+/// * `func` - Lookup table for function table entries
+/// * `vars` - A variable assignment for all child expressions
+/// * `module` - Module to be filled with constant/function/code storage
+/// * `oinfo` - Information needed for optimization
+/// * `alloc` - Scratch register allocator for the enclosing call frame
+///
+/// # Remarks
+///
+/// The scrutinee is loaded into `base` exactly once. A bounds check routes
+/// anything outside `[min, min + span)` straight to `default`; inside that
+/// range, `JTI` indexes a table of signed offsets (one entry per value in
+/// the span, relative to the `JTI` instruction itself) appended right
+/// after it. Entries for values inside the span that never appeared in
+/// `arms` (gaps in an otherwise dense chain) point back at `default`, so
+/// this matches the semantics of the nested conditionals it replaces
+/// exactly. `default` and every arm end by jumping to the merge point,
+/// except the last arm, which is laid out directly before the merge point
+/// and falls through to it.
+#[inline(always)]
+fn expr_switch(var: &str,
+              arms: &[(i64, &[Expression])],
+              min: i64,
+              span: u16,
+              default: &[Expression],
+              base: u8,
+              func: &mut FuncTable,
+              vars: &HashMap<String, (Type, Register)>,
+              module: &mut Module,
+              oinfo: &OptimizationInfo,
+              alloc: &mut RegisterAllocator) {
+    expr_variable(var, base, vars, module);
+
+    // Normalize the scrutinee into a zero-based table index.
+    let min_reg = alloc.alloc();
+    expr_integer(min, min_reg, module);
+    let index = alloc.alloc();
+    module.code.push(Instruction { opcode: ops::SUB, target: index, left: base, right: min_reg });
+    alloc.free(min_reg);
+
+    // Bounds check: `0 <= index < span`.
+    let zero_reg = alloc.alloc();
+    expr_integer(0, zero_reg, module);
+    let in_range = alloc.alloc();
+    module.code.push(Instruction { opcode: ops::GE, target: in_range, left: index, right: zero_reg });
+    alloc.free(zero_reg);
+    let span_reg = alloc.alloc();
+    expr_integer(span as i64, span_reg, module);
+    let below_span = alloc.alloc();
+    module.code.push(Instruction { opcode: ops::LT, target: below_span, left: index, right: span_reg });
+    alloc.free(span_reg);
+    module.code.push(Instruction { opcode: ops::AND, target: in_range, left: in_range, right: below_span });
+    alloc.free(below_span);
+
+    let branch_opti = OptimizationInfo {
+        func_name: oinfo.func_name,
+        tail: false,
+        level: oinfo.level
+    };
+
+    // If out of range, fall through into `default`; in range, jump past it
+    // (and the JMF that follows it) straight to the table dispatch below.
+    let jtf_index = module.code.len();
+    module.code.push(Instruction { opcode: ops::JTF, target: in_range, left: 0, right: 0 });
+    alloc.free(in_range);
+
+    let default_start = module.code.len();
+    for expr in &default[..default.len() - 1] {
+        generate_expression(expr, base, func, vars, module, &branch_opti, alloc);
+    }
+    generate_expression(&default[default.len() - 1], base, func, vars, module, oinfo, alloc);
+
+    let jmf_index = module.code.len();
+    module.code.push(Instruction { opcode: ops::JMF, target: 0, left: 0, right: 0 });
+
+    let offset = module.code.len() - jtf_index;
+    {
+        let jtf = &mut module.code[jtf_index];
+        jtf.left = offset as u8;
+        jtf.right = (offset >> 8) as u8;
+    }
+
+    // Dispatch: `index` selects one of `span` signed offset entries stored
+    // right after the `JTI` instruction; every arm follows the table.
+    let jti_index = module.code.len();
+    module.code.push(Instruction { opcode: ops::JTI, target: index, left: 0, right: 0 });
+    alloc.free(index);
+    let table_index = module.code.len();
+    for _ in 0..span {
+        module.code.push(Instruction { opcode: ops::HLT, target: 0, left: 0, right: 0 });
+    }
+
+    let mut claimed = vec![false; span as usize];
+    let mut offsets = vec![0i32; span as usize];
+    let mut merge_jumps = Vec::new();
+
+    for (i, &(value, body)) in arms.iter().enumerate() {
+        let arm_start = module.code.len();
+        let slot = (value - min) as usize;
+        offsets[slot] = arm_start as i32 - jti_index as i32;
+        claimed[slot] = true;
+
+        for expr in &body[..body.len() - 1] {
+            generate_expression(expr, base, func, vars, module, &branch_opti, alloc);
+        }
+        generate_expression(&body[body.len() - 1], base, func, vars, module, oinfo, alloc);
+
+        if i != arms.len() - 1 {
+            merge_jumps.push(module.code.len());
+            module.code.push(Instruction { opcode: ops::JMF, target: 0, left: 0, right: 0 });
+        }
+    }
+
+    // Gaps in the span that no arm claimed route back to `default`.
+    let default_delta = default_start as i32 - jti_index as i32;
+    for (slot, offset) in offsets.iter_mut().enumerate() {
+        if !claimed[slot] {
+            *offset = default_delta;
+        }
+    }
+    for (slot, delta) in offsets.into_iter().enumerate() {
+        let delta = i16::try_from(delta)
+            .expect("Jump table offset exceeded 16 bits.") as u16;
+        let entry = &mut module.code[table_index + slot];
+        entry.left = delta as u8;
+        entry.right = (delta >> 8) as u8;
+    }
+
+    let merge = module.code.len();
+    {
+        let offset = merge - jmf_index;
+        let jmf = &mut module.code[jmf_index];
+        jmf.target = offset as u8;
+        jmf.left = (offset >> 8) as u8;
+        jmf.right = (offset >> 16) as u8;
+    }
+    for idx in merge_jumps {
+        let offset = merge - idx;
+        let jmf = &mut module.code[idx];
+        jmf.target = offset as u8;
+        jmf.left = (offset >> 8) as u8;
+        jmf.right = (offset >> 16) as u8;
+    }
+}
+
 /// Generate instructions for a branching operation
 ///
 /// # Arguments
@@ -520,20 +1047,67 @@ fn expr_variable(name: &str,
 /// * `vaprs` - A variable assignment for all child expressions
 /// * `module` - Module to be filled with constant/function/code storage
 /// * `oinfo` - Information needed for optimization
+/// * `alloc` - Scratch register allocator for the enclosing call frame
+///
+/// # Remarks
+///
+/// At `OptimizationLevel::Simple` and above, when `cond` is a constant
+/// `Integer` (e.g. after constant folding), only the taken branch is
+/// generated and no jump instructions are emitted; at `None` this rewrite
+/// is skipped so `generate()` keeps its strict one-to-one lowering.
+/// Otherwise, at the same level and above, if `cond`/`yes`/`no` form a dense
+/// chain of `==` tests against the same variable (see `collect_switch_arms`),
+/// the chain is lowered to a jump-table dispatch (see `expr_switch`) instead
+/// of a JTF/JMF cascade.
 #[inline(always)]
 fn expr_conditional(cond: &Expression,
                     yes: &[Expression],
                     no: &[Expression],
                     base: u8,
-                    func: &mut HashMap<String, u32>,
+                    func: &mut FuncTable,
                     vars: &HashMap<String, (Type, Register)>,
                     module: &mut Module,
-                    oinfo: &OptimizationInfo) {
+                    oinfo: &OptimizationInfo,
+                    alloc: &mut RegisterAllocator) {
+    // A constant condition (typically folded by an earlier optimization
+    // pass) lets us skip the JTF/JMF jump machinery entirely and emit only
+    // the branch that is actually taken. Only applies once constant
+    // folding itself is enabled; at `OptimizationLevel::None` a literal
+    // condition still lowers to a real JTF/JMF pair.
+    if oinfo.level >= OptimizationLevel::Simple {
+        if let Integer(value) = *cond {
+            let branch = if value == 0 { no } else { yes };
+            let branch_opti = OptimizationInfo {
+                func_name: oinfo.func_name,
+                tail: false,
+                level: oinfo.level
+            };
+            for expr in &branch[..branch.len() - 1] {
+                generate_expression(expr, base, func, vars, module, &branch_opti, alloc);
+            }
+            generate_expression(&branch[branch.len() - 1], base, func, vars, module, oinfo, alloc);
+            return;
+        }
+    }
+
+    // Likewise, only collapse a dense `==` chain into a jump table once AST
+    // rewriting is allowed; at `OptimizationLevel::None` each `Conditional`
+    // still lowers one-to-one into its own JTF/JMF pair.
+    if oinfo.level >= OptimizationLevel::Simple {
+        if let Some((var, arms, default)) = collect_switch_arms(cond, yes, no) {
+            if let Some((min, span)) = switch_table_span(&arms) {
+                expr_switch(var, &arms, min, span, default, base, func, vars, module, oinfo, alloc);
+                return;
+            }
+        }
+    }
+
     let condition_opti = OptimizationInfo {
         func_name: oinfo.func_name,
-        tail: false
+        tail: false,
+        level: oinfo.level
     };
-    generate_expression(cond, base, func, vars, module, &condition_opti);
+    generate_expression(cond, base, func, vars, module, &condition_opti, alloc);
 
     let jmp_index = module.code.len();
     module.code.push(Instruction {
@@ -545,11 +1119,11 @@ fn expr_conditional(cond: &Expression,
 
     // Generate every expression except tail
     for expr in &no[..no.len()] {
-        generate_expression(expr, base, func, vars, module, &condition_opti);
+        generate_expression(expr, base, func, vars, module, &condition_opti, alloc);
     }
 
     // Generate tail expression
-    generate_expression(&no[no.len() - 1], base, func, vars, module, oinfo);
+    generate_expression(&no[no.len() - 1], base, func, vars, module, oinfo, alloc);
 
     let offset = module.code.len() - jmp_index + 1;
     {
@@ -568,11 +1142,11 @@ fn expr_conditional(cond: &Expression,
 
     // Generate every expression except tail
     for expr in &yes[..yes.len()] {
-        generate_expression(expr, base, func, vars, module, &condition_opti);
+        generate_expression(expr, base, func, vars, module, &condition_opti, alloc);
     }
 
     // Generate tail expression
-    generate_expression(&yes[yes.len() - 1], base, func, vars, module, oinfo);
+    generate_expression(&yes[yes.len() - 1], base, func, vars, module, oinfo, alloc);
 
     let offset = module.code.len() - jmp_index;
     {
@@ -582,3 +1156,92 @@ fn expr_conditional(cond: &Expression,
         jmp.right = (offset >> 16) as u8;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "Register pressure exceeded.")]
+    fn register_allocator_panics_past_256_live_registers() {
+        let mut alloc = RegisterAllocator::new(0);
+        for _ in 0..257 {
+            alloc.alloc();
+        }
+    }
+
+    #[test]
+    fn register_allocator_reuses_freed_registers() {
+        let mut alloc = RegisterAllocator::new(2);
+        let a = alloc.alloc();
+        let b = alloc.alloc();
+        assert_ne!(a, b);
+        alloc.free(a);
+        // The most recently freed register is handed back out first, so this
+        // does not grow past `b`.
+        let c = alloc.alloc();
+        assert_eq!(a, c);
+    }
+
+    /// Builds `let x = 0 in if x == 0 then 100 else if x == 1 then 200 else
+    /// if x == 3 then 400 else 999`: a dense enough `==` chain (span 4 over
+    /// 3 arms) to lower to a jump table, with a deliberate gap at `x == 2` to
+    /// exercise default-arm routing for unclaimed slots.
+    fn dense_equality_chain_with_gap() -> Expression {
+        let arm3 = Conditional(Box::new(BinaryOp("==".to_string(),
+                                                  Box::new(Variable("x".to_string())),
+                                                  Box::new(Integer(3)))),
+                                vec![Integer(400)],
+                                vec![Integer(999)]);
+        let arm1 = Conditional(Box::new(BinaryOp("==".to_string(),
+                                                  Box::new(Variable("x".to_string())),
+                                                  Box::new(Integer(1)))),
+                                vec![Integer(200)],
+                                vec![arm3]);
+        let arm0 = Conditional(Box::new(BinaryOp("==".to_string(),
+                                                  Box::new(Variable("x".to_string())),
+                                                  Box::new(Integer(0)))),
+                                vec![Integer(100)],
+                                vec![arm1]);
+        VariableAssignment(vec![("x".to_string(), Integer(0))], vec![arm0])
+    }
+
+    #[test]
+    fn expr_switch_jump_table_routes_arms_and_gap_to_default() {
+        let module = generate_with_level(&[dense_equality_chain_with_gap()], OptimizationLevel::Simple);
+
+        let jtf_index = module.code.iter().position(|i| i.opcode == ops::JTF)
+            .expect("bounds check should have been generated");
+        let jti_index = module.code.iter().position(|i| i.opcode == ops::JTI)
+            .expect("dense == chain should have been lowered to a jump table");
+        assert!(jtf_index < jti_index);
+
+        // `default_start` sits right after the JTF that falls through into it.
+        let default_start = jtf_index + 1;
+        let expected_default_delta = default_start as i32 - jti_index as i32;
+
+        let decode = |slot: usize| -> i32 {
+            let entry = &module.code[jti_index + 1 + slot];
+            assert_eq!(entry.opcode, ops::HLT, "table slots keep their HLT placeholder opcode");
+            let raw = (entry.left as u16) | ((entry.right as u16) << 8);
+            i32::from(raw as i16)
+        };
+
+        // Values 0, 1 and 3 are claimed by arms; value 2 is the gap and must
+        // fall back to the default arm's offset.
+        let offsets: Vec<i32> = (0..4).map(decode).collect();
+        assert_eq!(offsets[2], expected_default_delta, "gap slot should route to the default arm");
+        for &slot in &[0, 1, 3] {
+            assert!(offsets[slot] > 0, "claimed arms sit after the table, so their offset is positive");
+        }
+        let claimed: HashSet<i32> = [offsets[0], offsets[1], offsets[3]].iter().cloned().collect();
+        assert_eq!(claimed.len(), 3, "each claimed arm should get a distinct table entry");
+    }
+
+    #[test]
+    fn expr_switch_not_used_below_optimization_level_simple() {
+        let module = generate_with_level(&[dense_equality_chain_with_gap()], OptimizationLevel::None);
+        assert!(module.code.iter().all(|i| i.opcode != ops::JTI),
+                "jump-table lowering must not fire at OptimizationLevel::None");
+    }
+}
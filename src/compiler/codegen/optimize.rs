@@ -0,0 +1,255 @@
+/// Pre-codegen optimization passes over the parser's `Expression` tree.
+///
+/// These passes run on the AST before instructions are emitted, so the
+/// codegen side stays a straightforward structural walk. Passes are
+/// additive: each optimization level runs every pass of the levels below it.
+use std::collections::HashMap;
+use compiler::parser::{Expression, Expression::*};
+
+/// Controls which AST-level optimization passes `generate_with_level` runs
+/// before handing the tree to codegen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptimizationLevel {
+    /// No AST rewriting, expressions are lowered one-to-one.
+    None,
+    /// Constant folding of arithmetic/comparison/bitwise expressions.
+    Simple,
+    /// Everything in `Simple`, plus eager evaluation of pure function calls.
+    Full,
+}
+
+/// Maximum number of sub-expressions a single compile-time call evaluation
+/// is allowed to walk before the fold is abandoned and a normal call is
+/// emitted instead. Guards against runaway recursion in the tree-walking
+/// interpreter used by `eval_call`.
+pub(crate) const EVAL_STEP_BUDGET: u32 = 10_000;
+
+/// Everything codegen needs to know about a compiled function: its
+/// function-table index for `CAL`/`JMP` encoding, whether it is "pure" (safe
+/// to evaluate at compile time), and, when pure, its parameter names and
+/// body for `eval_call`.
+pub struct FunctionInfo {
+    pub index: u32,
+    pub pure: bool,
+    pub param: Vec<String>,
+    pub body: Vec<Expression>,
+}
+
+/// Lookup table from function name to its codegen/optimization metadata.
+pub type FuncTable = HashMap<String, FunctionInfo>;
+
+/// Determine whether the body of the function currently named `name` only
+/// performs pure computation, i.e. it is safe to evaluate at compile time.
+///
+/// # Remarks
+///
+/// A function is pure when it never calls `read`/`write`, and only calls
+/// itself or other functions that have themselves already been recorded as
+/// pure in `func`. Direct self-recursion is allowed through: `eval_call`
+/// bounds its evaluation with `EVAL_STEP_BUDGET`, so a self-recursive pure
+/// function (e.g. `fact`/`fib`) still cannot blow up compile time. Since
+/// other functions are analyzed in definition order, this only recognizes
+/// purity through functions defined earlier in the module.
+pub fn is_pure_body(name: &str, body: &[Expression], func: &FuncTable) -> bool {
+    body.iter().all(|expr| is_pure_expr(name, expr, func))
+}
+
+fn is_pure_expr(name: &str, expr: &Expression, func: &FuncTable) -> bool {
+    match *expr {
+        Integer(_) | Variable(_) => true,
+        BinaryOp(_, ref left, ref right) => {
+            is_pure_expr(name, left, func) && is_pure_expr(name, right, func)
+        }
+        UnaryOp(ref op, ref left) => op != "write" && is_pure_expr(name, left, func),
+        NullaryOp(ref op) => op != "read",
+        Function(ref callee, ref param) => {
+            (callee == name || func.get(callee).map_or(false, |f| f.pure))
+                && param.iter().all(|p| is_pure_expr(name, p, func))
+        }
+        VariableAssignment(ref assignments, ref body) => {
+            assignments.iter().all(|&(_, ref expr)| is_pure_expr(name, expr, func))
+                && body.iter().all(|expr| is_pure_expr(name, expr, func))
+        }
+        Conditional(ref cond, ref yes, ref no) => {
+            is_pure_expr(name, cond, func)
+                && yes.iter().all(|expr| is_pure_expr(name, expr, func))
+                && no.iter().all(|expr| is_pure_expr(name, expr, func))
+        }
+        FunctionDefinition(_, _, ref body) => {
+            body.iter().all(|expr| is_pure_expr(name, expr, func))
+        }
+    }
+}
+
+/// Attempt to evaluate a call to a pure function with constant `args` at
+/// compile time.
+///
+/// # Arguments
+///
+/// * `budget` - Steps remaining before the eager evaluator gives up; shared
+///   with and decremented by every nested call this one makes, so it bounds
+///   the total work of a top-level fold attempt rather than each call's own
+///   body in isolation
+///
+/// # Remarks
+///
+/// Returns `None` if the step budget runs out or a division by zero is
+/// encountered, in which case the caller should fall back to emitting a
+/// normal call.
+pub fn eval_call(entry: &FunctionInfo, args: &[i64], func: &FuncTable, budget: &mut u32) -> Option<i64> {
+    let env = entry.param.iter().cloned().zip(args.iter().cloned()).collect();
+    eval_body(&entry.body, &env, func, budget)
+}
+
+fn eval_body(body: &[Expression],
+             env: &HashMap<String, i64>,
+             func: &FuncTable,
+             budget: &mut u32) -> Option<i64> {
+    let mut result = None;
+    for expr in body {
+        result = Some(eval_expr(expr, env, func, budget)?);
+    }
+    result
+}
+
+fn eval_expr(expr: &Expression,
+             env: &HashMap<String, i64>,
+             func: &FuncTable,
+             budget: &mut u32) -> Option<i64> {
+    *budget = budget.checked_sub(1)?;
+    match *expr {
+        Integer(value) => Some(value),
+        Variable(ref name) => env.get(name).cloned(),
+        BinaryOp(ref op, ref left, ref right) => {
+            let left = eval_expr(left, env, func, budget)?;
+            let right = eval_expr(right, env, func, budget)?;
+            fold_binary(op, left, right)
+        }
+        UnaryOp(ref op, ref left) => {
+            let left = eval_expr(left, env, func, budget)?;
+            fold_unary(op, left)
+        }
+        NullaryOp(_) => None,
+        Function(ref name, ref param) => {
+            let entry = func.get(name)?;
+            if !entry.pure {
+                return None;
+            }
+            let args = param.iter()
+                .map(|p| eval_expr(p, env, func, budget))
+                .collect::<Option<Vec<i64>>>()?;
+            eval_call(entry, &args, func, budget)
+        }
+        VariableAssignment(ref assignments, ref body) => {
+            let mut env = env.clone();
+            for &(ref name, ref expr) in assignments {
+                let value = eval_expr(expr, &env, func, budget)?;
+                env.insert(name.clone(), value);
+            }
+            eval_body(body, &env, func, budget)
+        }
+        Conditional(ref cond, ref yes, ref no) => {
+            let value = eval_expr(cond, env, func, budget)?;
+            let branch = if value == 0 { no } else { yes };
+            eval_body(branch, env, func, budget)
+        }
+        FunctionDefinition(..) => None,
+    }
+}
+
+/// Recursively fold constant sub-expressions of `expr` into `Integer` nodes.
+///
+/// # Arguments
+///
+/// * `expr` - Root of the AST to fold
+///
+/// # Remarks
+///
+/// `read`/`write` are never folded since they perform I/O, and `/` is never
+/// folded when the divisor is a constant `0` so that the runtime division
+/// error is still raised at the original call site.
+pub fn fold_constants(expr: &Expression) -> Expression {
+    match *expr {
+        Integer(value) => Integer(value),
+        BinaryOp(ref op, ref left, ref right) => {
+            let left = fold_constants(left);
+            let right = fold_constants(right);
+            if let (&Integer(l), &Integer(r)) = (&left, &right) {
+                if let Some(value) = fold_binary(op, l, r) {
+                    return Integer(value);
+                }
+            }
+            BinaryOp(op.clone(), Box::new(left), Box::new(right))
+        }
+        UnaryOp(ref op, ref left) => {
+            let left = fold_constants(left);
+            if let &Integer(l) = &left {
+                if let Some(value) = fold_unary(op, l) {
+                    return Integer(value);
+                }
+            }
+            UnaryOp(op.clone(), Box::new(left))
+        }
+        NullaryOp(ref op) => NullaryOp(op.clone()),
+        Function(ref name, ref param) => {
+            Function(name.clone(), param.iter().map(fold_constants).collect())
+        }
+        FunctionDefinition(ref name, ref param, ref body) => {
+            FunctionDefinition(name.clone(),
+                               param.clone(),
+                               body.iter().map(fold_constants).collect())
+        }
+        VariableAssignment(ref assignments, ref body) => {
+            let assignments = assignments.iter()
+                .map(|&(ref name, ref expr)| (name.clone(), fold_constants(expr)))
+                .collect();
+            VariableAssignment(assignments, body.iter().map(fold_constants).collect())
+        }
+        Variable(ref name) => Variable(name.clone()),
+        Conditional(ref cond, ref yes, ref no) => {
+            Conditional(Box::new(fold_constants(cond)),
+                        yes.iter().map(fold_constants).collect(),
+                        no.iter().map(fold_constants).collect())
+        }
+    }
+}
+
+/// Evaluate a binary operation on two constant operands at compile time.
+///
+/// Returns `None` when the operation must not be folded, i.e. division by
+/// a constant zero, which is left intact so the runtime raises the error.
+fn fold_binary(op: &str, left: i64, right: i64) -> Option<i64> {
+    let value = match op {
+        "+" => left.wrapping_add(right),
+        "-" => left.wrapping_sub(right),
+        "*" => left.wrapping_mul(right),
+        "/" => {
+            if right == 0 {
+                return None;
+            }
+            left.wrapping_div(right)
+        }
+        "&" => left & right,
+        "|" => left | right,
+        "==" => (left == right) as i64,
+        "<" => (left < right) as i64,
+        "<=" => (left <= right) as i64,
+        ">" => (left > right) as i64,
+        ">=" => (left >= right) as i64,
+        "!=" => (left != right) as i64,
+        _ => panic!("Invalid operation")
+    };
+    Some(value)
+}
+
+/// Evaluate a unary operation on a constant operand at compile time.
+///
+/// Returns `None` for `write`, which has an I/O side effect and must always
+/// be emitted regardless of its operand being constant.
+fn fold_unary(op: &str, left: i64) -> Option<i64> {
+    match op {
+        "~" => Some(!left),
+        "write" => None,
+        _ => panic!("Invalid operation")
+    }
+}